@@ -0,0 +1,20 @@
+/// Request payloads handed across the FFI boundary, as opposed to
+/// `video`'s internal worker-protocol types.
+pub mod payloads {
+    use crate::video::{OutputFormat, ScalingAlgorithm, ToneMapTarget};
+
+    /// A single video layer to decode a frame from. `frame` is given in the
+    /// composition's own fps; `tone_map_target` controls whether an HDR
+    /// source is tone-mapped down to SDR BT.709 before compositing;
+    /// `scaling_algorithm` and `output_format` pick the resampling filter
+    /// and pixel format the decoded frame is scaled into.
+    pub struct VideoLayer {
+        pub src: String,
+        pub frame: u32,
+        pub width: u32,
+        pub height: u32,
+        pub tone_map_target: ToneMapTarget,
+        pub scaling_algorithm: ScalingAlgorithm,
+        pub output_format: OutputFormat,
+    }
+}
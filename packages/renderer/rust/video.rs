@@ -1,5 +1,8 @@
 extern crate ffmpeg_next as ffmpeg;
+extern crate ffmpeg_sys_next as ffmpeg_sys;
+use std::ffi::c_void;
 use std::sync::mpsc::{self, Sender};
+use std::sync::{Mutex, OnceLock};
 
 use std::thread;
 use std::{
@@ -17,77 +20,811 @@ use ffmpeg::{
 use crate::errors::{handle_error, print_debug};
 use crate::payloads::payloads::VideoLayer;
 
+/// Something a `VideoLayer` can read exact-offset bytes from. A filesystem
+/// path still goes through `ffmpeg::format::input` directly; this trait is
+/// for everything else an `AVIOContext` can stand in for: an in-memory
+/// buffer, an HTTP(S) URL read lazily in ranges, or an S3-style object.
+pub trait FrameSource: Send {
+    /// Fills as much of `buf` as is available and returns the number of
+    /// bytes written, or 0 at end of stream.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+    /// Seeks to `offset` interpreted per `whence` (`libc::SEEK_SET` /
+    /// `SEEK_CUR` / `SEEK_END`) and returns the resulting absolute offset.
+    fn seek(&mut self, offset: i64, whence: i32) -> i64;
+}
+
+unsafe extern "C" fn read_packet(opaque: *mut c_void, buf: *mut u8, buf_size: i32) -> i32 {
+    let source = &mut *(opaque as *mut Box<dyn FrameSource>);
+    let slice = std::slice::from_raw_parts_mut(buf, buf_size as usize);
+    match source.read(slice) {
+        0 => ffmpeg_sys::AVERROR_EOF,
+        read => read as i32,
+    }
+}
+
+unsafe extern "C" fn seek_source(opaque: *mut c_void, offset: i64, whence: i32) -> i64 {
+    let source = &mut *(opaque as *mut Box<dyn FrameSource>);
+    source.seek(offset, whence)
+}
+
+const AVIO_BUFFER_SIZE: usize = 4096;
+
+/// Owns a libav `AVIOContext` built from a boxed `FrameSource`, and frees
+/// both the context and its read buffer when dropped. The `FrameSource`
+/// itself is kept alive behind the context's `opaque` pointer (boxed twice
+/// so the pointer handed to libav is thin) and is dropped from there too.
+struct AVIO {
+    ctx: *mut ffmpeg_sys::AVIOContext,
+}
+
+impl AVIO {
+    fn new(source: Box<dyn FrameSource>) -> Self {
+        let opaque = Box::into_raw(Box::new(source)) as *mut c_void;
+        let ctx = unsafe {
+            let buffer = ffmpeg_sys::av_malloc(AVIO_BUFFER_SIZE) as *mut u8;
+            ffmpeg_sys::avio_alloc_context(
+                buffer,
+                AVIO_BUFFER_SIZE as i32,
+                0,
+                opaque,
+                Some(read_packet),
+                None,
+                Some(seek_source),
+            )
+        };
+        AVIO { ctx }
+    }
+}
+
+impl Drop for AVIO {
+    fn drop(&mut self) {
+        unsafe {
+            let opaque = (*self.ctx).opaque;
+            drop(Box::from_raw(opaque as *mut Box<dyn FrameSource>));
+            ffmpeg_sys::av_freep(&mut (*self.ctx).buffer as *mut _ as *mut c_void);
+            ffmpeg_sys::avio_context_free(&mut self.ctx);
+        }
+    }
+}
+
+/// Sources registered by key so a `VideoLayer` can name one instead of a
+/// filesystem path. Registering is a one-shot hand-off: opening the source
+/// takes it out of the registry and wraps it in the `AVIOContext` libav
+/// reads through.
+static SOURCE_REGISTRY: OnceLock<Mutex<HashMap<String, Box<dyn FrameSource>>>> = OnceLock::new();
+
+pub fn register_frame_source(key: String, source: Box<dyn FrameSource>) {
+    SOURCE_REGISTRY
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .insert(key, source);
+}
+
+fn take_registered_frame_source(key: &str) -> Option<Box<dyn FrameSource>> {
+    SOURCE_REGISTRY
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .unwrap()
+        .remove(key)
+}
+
+/// Opens `src` as an `Input`, routing through a registered `FrameSource`'s
+/// `AVIOContext` when one is registered under that key, and otherwise
+/// falling back to `ffmpeg::format::input` for a plain filesystem path.
+///
+/// Returns the `AVIO` ahead of the `Input` so callers can bind them as
+/// `let (avio, input) = open_input(..)?;` — locals drop in reverse
+/// declaration order, so `input` (declared last) drops first, closing the
+/// format context — which can still touch `pb` via the demuxer's
+/// `read_close` — before the `AVIOContext` underneath it is freed. See
+/// `OpenDecoder`, which keeps the same ordering via its field order.
+fn open_input(src: &str) -> Result<(Option<AVIO>, ffmpeg::format::context::Input), ffmpeg::Error> {
+    let Some(source) = take_registered_frame_source(src) else {
+        return Ok((None, ffmpeg::format::input(src)?));
+    };
+
+    let avio = AVIO::new(source);
+
+    unsafe {
+        let mut format_context = ffmpeg_sys::avformat_alloc_context();
+        (*format_context).pb = avio.ctx;
+
+        let empty = std::ffi::CString::new("").unwrap();
+        let status = ffmpeg_sys::avformat_open_input(
+            &mut format_context,
+            empty.as_ptr(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        );
+        if status < 0 {
+            ffmpeg_sys::avformat_free_context(format_context);
+            return Err(ffmpeg::Error::from(status));
+        }
+
+        let status = ffmpeg_sys::avformat_find_stream_info(format_context, std::ptr::null_mut());
+        if status < 0 {
+            ffmpeg_sys::avformat_close_input(&mut format_context);
+            return Err(ffmpeg::Error::from(status));
+        }
+
+        // `Input::wrap` takes ownership of an already-opened
+        // `AVFormatContext` and installs the normal `avformat_close_input`
+        // `Drop` impl on it, so we don't need to assume anything about the
+        // type's layout the way a transmute would.
+        let input = ffmpeg::format::context::Input::wrap(format_context);
+        Ok((Some(avio), input))
+    }
+}
+
+/// A request for a single decoded frame, serviced by whichever worker in
+/// the `process_frames` pool owns `src`. `reply` is a one-shot channel the
+/// worker sends the result back on, so many requests can be in flight
+/// without the caller having to demultiplex a shared response stream.
+pub struct FrameRequest {
+    pub src: String,
+    pub frame: u32,
+    pub width: u32,
+    pub height: u32,
+    pub tone_map_target: ToneMapTarget,
+    pub scaling_algorithm: ScalingAlgorithm,
+    pub output_format: OutputFormat,
+    pub reply: Sender<Result<Vec<u8>, io::Error>>,
+}
+
+/// A source kept open on a worker thread across calls so repeated requests
+/// for the same file don't pay for reopening the container and rebuilding
+/// the decoder every time.
+struct OpenDecoder {
+    // Declared before `_avio` so it drops (and closes the format context)
+    // before the `AVIOContext` backing it is freed.
+    input: ffmpeg::format::context::Input,
+    stream_index: usize,
+    decoder: ffmpeg::decoder::Video,
+    time_base: ffmpeg::Rational,
+    frame_rate: ffmpeg::Rational,
+    last_frame: Option<u32>,
+    _avio: Option<AVIO>,
+}
+
+impl OpenDecoder {
+    fn open(src: &str) -> Result<Self, ffmpeg::Error> {
+        let (avio, input) = open_input(src)?;
+        let stream = input
+            .streams()
+            .best(Type::Video)
+            .ok_or(ffmpeg::Error::StreamNotFound)?;
+        let stream_index = stream.index();
+        let time_base = stream.time_base();
+        let frame_rate = stream.rate();
+        let context_decoder =
+            ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
+        let decoder = context_decoder.decoder().video()?;
+
+        Ok(OpenDecoder {
+            input,
+            stream_index,
+            decoder,
+            time_base,
+            frame_rate,
+            last_frame: None,
+            _avio: avio,
+        })
+    }
+
+    /// Decodes `frame` into a buffer sized `width`x`height`, in
+    /// `output_format`, decoding forward from wherever the stream currently
+    /// sits and only seeking when the target lies behind the current
+    /// position or the current position is not known.
+    #[allow(clippy::too_many_arguments)]
+    fn decode(
+        &mut self,
+        frame: u32,
+        width: u32,
+        height: u32,
+        video_fps: u32,
+        tone_map_target: ToneMapTarget,
+        scaling_algorithm: ScalingAlgorithm,
+        output_format: OutputFormat,
+    ) -> Result<Vec<u8>, ffmpeg::Error> {
+        let needs_seek = match self.last_frame {
+            Some(last) => frame <= last,
+            None => true,
+        };
+
+        if needs_seek {
+            seek_to_frame(&mut self.input, &mut self.decoder, self.time_base, frame, video_fps)?;
+        }
+
+        let mut scaler = Context::get(
+            self.decoder.format(),
+            self.decoder.width(),
+            self.decoder.height(),
+            output_format.pixel(),
+            width,
+            height,
+            scaling_algorithm.flags(),
+        )?;
+
+        let (result, exact) = decode_exact_frame(
+            &mut self.input,
+            &mut self.decoder,
+            &mut scaler,
+            self.stream_index,
+            self.time_base,
+            self.frame_rate,
+            frame,
+            video_fps,
+            tone_map_target,
+            output_format,
+            width,
+            height,
+        )?;
+
+        // Only trust the stream's position as "at `frame`" when the decoded
+        // index matched exactly. An overshoot (VFR / a missing index) means
+        // the stream is actually past `frame`, so the next request — even
+        // for a later frame — must re-seek rather than assume it can keep
+        // decoding forward from here.
+        self.last_frame = if exact { Some(frame) } else { None };
+        Ok(result)
+    }
+}
+
+/// Converts a target frame number into a stream timestamp and seeks there.
+/// Passing the target as the timestamp and as the upper end of a range that
+/// is open below makes `avformat_seek_file` land on the nearest keyframe at
+/// or before it (the "backward" behaviour),
+/// which is what lets us decode forward from a keyframe to the exact frame.
+fn seek_to_frame(
+    input: &mut ffmpeg::format::context::Input,
+    decoder: &mut ffmpeg::decoder::Video,
+    time_base: ffmpeg::Rational,
+    frame: u32,
+    video_fps: u32,
+) -> Result<(), ffmpeg::Error> {
+    let time = frame as f64 / video_fps as f64;
+    let position = (time * time_base.1 as f64 / time_base.0 as f64) as i64;
+    input.seek(position, ..position)?;
+    decoder.flush();
+    Ok(())
+}
+
+/// Whether a decoded frame's transfer characteristic should be tone-mapped
+/// down to SDR before it's composited, or left as-is. Lives on `VideoLayer`
+/// so a layer that wants the raw HDR samples (e.g. to hand to an HDR-aware
+/// encoder downstream) can opt out.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapTarget {
+    SdrBt709,
+    Passthrough,
+}
+
+/// Resampling algorithm a `VideoLayer` picks for its scaler, mapped onto
+/// the matching swscale `Flags`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ScalingAlgorithm {
+    Bilinear,
+    Bicubic,
+    Lanczos,
+    Neighbor,
+}
+
+impl ScalingAlgorithm {
+    fn flags(self) -> Flags {
+        match self {
+            ScalingAlgorithm::Bilinear => Flags::BILINEAR,
+            ScalingAlgorithm::Bicubic => Flags::BICUBIC,
+            ScalingAlgorithm::Lanczos => Flags::LANCZOS,
+            ScalingAlgorithm::Neighbor => Flags::POINT,
+        }
+    }
+}
+
+/// Output pixel format a `VideoLayer` decodes into: RGB24 for the common
+/// case, RGBA to keep alpha from VP9/ProRes sources, or planar YUV420P to
+/// skip the RGB conversion entirely when the consumer wants YUV.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Rgb24,
+    Rgba,
+    Yuv420p,
+}
+
+impl OutputFormat {
+    fn pixel(self) -> Pixel {
+        match self {
+            OutputFormat::Rgb24 => Pixel::RGB24,
+            OutputFormat::Rgba => Pixel::RGBA,
+            OutputFormat::Yuv420p => Pixel::YUV420P,
+        }
+    }
+
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            OutputFormat::Rgb24 => 3,
+            OutputFormat::Rgba => 4,
+            OutputFormat::Yuv420p => 1,
+        }
+    }
+}
+
+/// Copies every plane of a decoded/scaled frame in `format` out of its
+/// (possibly padded) libav buffers into a tightly packed byte vector, using
+/// the real bytes-per-pixel of `format` rather than assuming RGB24. Planar
+/// formats walk each plane at its own (sub-sampled, for chroma) dimensions
+/// instead of only plane 0.
+fn copy_frame_planes(frame: &Video, format: OutputFormat) -> Vec<u8> {
+    if format == OutputFormat::Yuv420p {
+        let mut data = Vec::new();
+        for plane in 0..3 {
+            let stride = frame.stride(plane);
+            let (plane_width, plane_height) = if plane == 0 {
+                (frame.width() as usize, frame.height() as usize)
+            } else {
+                (
+                    (frame.width() as usize + 1) / 2,
+                    (frame.height() as usize + 1) / 2,
+                )
+            };
+            let plane_data = frame.data(plane);
+            for line in 0..plane_height {
+                let begin = line * stride;
+                let end = begin + plane_width;
+                data.extend_from_slice(&plane_data[begin..end]);
+            }
+        }
+        return data;
+    }
+
+    // https://github.com/zmwangx/rust-ffmpeg/issues/64
+    let stride = frame.stride(0);
+    let byte_width = frame.width() as usize * format.bytes_per_pixel();
+    let height = frame.height() as usize;
+    let plane_data = frame.data(0);
+    let mut data = Vec::with_capacity(byte_width * height);
+    for line in 0..height {
+        let begin = line * stride;
+        let end = begin + byte_width;
+        data.extend_from_slice(&plane_data[begin..end]);
+    }
+    data
+}
+
+fn is_hdr(
+    transfer: ffmpeg::color::TransferCharacteristic,
+    primaries: ffmpeg::color::Primaries,
+) -> bool {
+    let pq_or_hlg = matches!(
+        transfer,
+        ffmpeg::color::TransferCharacteristic::SMPTE2084
+            | ffmpeg::color::TransferCharacteristic::ARIB_STD_B67
+    );
+    pq_or_hlg && primaries == ffmpeg::color::Primaries::BT2020
+}
+
+const REFERENCE_WHITE_NITS: f32 = 100.0;
+
+/// SMPTE ST 2084 (PQ) EOTF, normalized so 1.0 is `REFERENCE_WHITE_NITS`.
+fn pq_eotf(signal: f32) -> f32 {
+    const M1: f32 = 1305.0 / 8192.0;
+    const M2: f32 = 2523.0 / 32.0;
+    const C1: f32 = 107.0 / 128.0;
+    const C2: f32 = 2413.0 / 128.0;
+    const C3: f32 = 2392.0 / 128.0;
+
+    let signal = signal.clamp(0.0, 1.0);
+    let inverted = signal.powf(1.0 / M2);
+    let num = (inverted - C1).max(0.0);
+    let den = C2 - C3 * inverted;
+    let linear_10000_nits = (num / den).powf(1.0 / M1);
+    linear_10000_nits * 10000.0 / REFERENCE_WHITE_NITS
+}
+
+/// ARIB STD-B67 (HLG) inverse OETF, giving scene-linear light in [0, 1],
+/// where 1.0 is the peak signal (1.0), not reference white.
+fn hlg_eotf(signal: f32) -> f32 {
+    const A: f32 = 0.178_832_77;
+    const B: f32 = 1.0 - 4.0 * A;
+    // C = 0.5 - A * ln(4A); precomputed since `ln` isn't a const fn.
+    const C: f32 = 0.559_910_73;
+
+    let signal = signal.clamp(0.0, 1.0);
+    if signal <= 0.5 {
+        (signal * signal) / 3.0
+    } else {
+        (((signal - C) / A).exp() + B) / 12.0
+    }
+}
+
+/// BT.2020 -> BT.709 3x3 gamut matrix applied in linear light.
+const BT2020_TO_BT709: [[f32; 3]; 3] = [
+    [1.6605, -0.5876, -0.0728],
+    [-0.1246, 1.1329, -0.0083],
+    [-0.0182, -0.1006, 1.1187],
+];
+
+fn apply_gamut_matrix(rgb: [f32; 3], matrix: &[[f32; 3]; 3]) -> [f32; 3] {
+    [
+        matrix[0][0] * rgb[0] + matrix[0][1] * rgb[1] + matrix[0][2] * rgb[2],
+        matrix[1][0] * rgb[0] + matrix[1][1] * rgb[1] + matrix[1][2] * rgb[2],
+        matrix[2][0] * rgb[0] + matrix[2][1] * rgb[1] + matrix[2][2] * rgb[2],
+    ]
+}
+
+/// Reinhard operator, compressing unbounded linear luminance into [0, 1).
+fn reinhard_tonemap(l: f32) -> f32 {
+    l / (1.0 + l)
+}
+
+/// Rec.709 luma weights, used to compute the luminance a Reinhard
+/// compression ratio is derived from.
+const BT709_LUMA_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Compresses `rgb` by its Reinhard-tonemapped luminance rather than
+/// compressing each channel independently, which would shift hue and
+/// desaturate bright colors.
+fn reinhard_tonemap_rgb(rgb: [f32; 3]) -> [f32; 3] {
+    let luminance = BT709_LUMA_WEIGHTS[0] * rgb[0]
+        + BT709_LUMA_WEIGHTS[1] * rgb[1]
+        + BT709_LUMA_WEIGHTS[2] * rgb[2];
+    if luminance <= 0.0 {
+        return [0.0, 0.0, 0.0];
+    }
+    let scale = reinhard_tonemap(luminance) / luminance;
+    [rgb[0] * scale, rgb[1] * scale, rgb[2] * scale]
+}
+
+fn bt709_oetf(linear: f32) -> f32 {
+    let linear = linear.clamp(0.0, 1.0);
+    if linear < 0.018 {
+        4.5 * linear
+    } else {
+        1.099 * linear.powf(0.45) - 0.099
+    }
+}
+
+/// Linearizes a PQ/HLG BT.2020 frame, converts it into the BT.709 gamut,
+/// compresses its luminance into SDR range with a Reinhard operator, then
+/// re-applies the BT.709 gamma curve, converting the result into
+/// `output_format` so the layer's requested format is honored regardless of
+/// whether the source needed tone mapping. `decoded` is first scaled into
+/// 16-bit RGB so the math runs at full decoder precision rather than on
+/// already-quantized 8-bit samples.
+fn tonemap_hdr_frame(
+    decoded: &Video,
+    transfer: ffmpeg::color::TransferCharacteristic,
+    output_format: OutputFormat,
+    target_width: u32,
+    target_height: u32,
+) -> Result<Vec<u8>, ffmpeg::Error> {
+    let mut linear_scaler = Context::get(
+        decoded.format(),
+        decoded.width(),
+        decoded.height(),
+        Pixel::RGB48LE,
+        target_width,
+        target_height,
+        Flags::BILINEAR,
+    )?;
+
+    let mut linear_rgb = Video::empty();
+    linear_scaler.run(decoded, &mut linear_rgb)?;
+
+    let eotf: fn(f32) -> f32 = if transfer == ffmpeg::color::TransferCharacteristic::ARIB_STD_B67 {
+        hlg_eotf
+    } else {
+        pq_eotf
+    };
+
+    let stride = linear_rgb.stride(0);
+    let width = linear_rgb.width() as usize;
+    let height = linear_rgb.height() as usize;
+    let data = linear_rgb.data(0);
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for line in 0..height {
+        let row = &data[line * stride..line * stride + width * 6];
+        for pixel in row.chunks_exact(6) {
+            let r = u16::from_le_bytes([pixel[0], pixel[1]]) as f32 / 65535.0;
+            let g = u16::from_le_bytes([pixel[2], pixel[3]]) as f32 / 65535.0;
+            let b = u16::from_le_bytes([pixel[4], pixel[5]]) as f32 / 65535.0;
+
+            let linear_709 = apply_gamut_matrix([eotf(r), eotf(g), eotf(b)], &BT2020_TO_BT709);
+            let compressed = reinhard_tonemap_rgb(linear_709);
+
+            rgb.push((bt709_oetf(compressed[0]) * 255.0).round() as u8);
+            rgb.push((bt709_oetf(compressed[1]) * 255.0).round() as u8);
+            rgb.push((bt709_oetf(compressed[2]) * 255.0).round() as u8);
+        }
+    }
+
+    Ok(rgb24_to_output_format(&rgb, width, height, output_format))
+}
+
+/// Repacks tone-mapped RGB24 bytes into `format`, so a tone-mapped HDR
+/// source still comes out in whichever format the layer requested instead
+/// of only ever producing RGB24.
+fn rgb24_to_output_format(rgb: &[u8], width: usize, height: usize, format: OutputFormat) -> Vec<u8> {
+    match format {
+        OutputFormat::Rgb24 => rgb.to_vec(),
+        OutputFormat::Rgba => {
+            let mut out = Vec::with_capacity(width * height * 4);
+            for pixel in rgb.chunks_exact(3) {
+                out.extend_from_slice(pixel);
+                out.push(255);
+            }
+            out
+        }
+        OutputFormat::Yuv420p => rgb24_to_yuv420p(rgb, width, height),
+    }
+}
+
+/// Converts packed RGB24 into planar YUV420P using full-range BT.709
+/// coefficients, averaging each 2x2 luma block down to one chroma sample.
+fn rgb24_to_yuv420p(rgb: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut y_plane = vec![0u8; width * height];
+    for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        y_plane[i] = (0.2126 * r + 0.7152 * g + 0.0722 * b).round().clamp(0.0, 255.0) as u8;
+    }
+
+    let chroma_width = width.div_ceil(2);
+    let chroma_height = height.div_ceil(2);
+    let mut cb_plane = vec![0u8; chroma_width * chroma_height];
+    let mut cr_plane = vec![0u8; chroma_width * chroma_height];
+
+    for cy in 0..chroma_height {
+        for cx in 0..chroma_width {
+            let mut r_sum = 0.0;
+            let mut g_sum = 0.0;
+            let mut b_sum = 0.0;
+            for dy in 0..2 {
+                for dx in 0..2 {
+                    let x = (cx * 2 + dx).min(width - 1);
+                    let y = (cy * 2 + dy).min(height - 1);
+                    let pixel = &rgb[(y * width + x) * 3..(y * width + x) * 3 + 3];
+                    r_sum += pixel[0] as f32;
+                    g_sum += pixel[1] as f32;
+                    b_sum += pixel[2] as f32;
+                }
+            }
+            let (r, g, b) = (r_sum / 4.0, g_sum / 4.0, b_sum / 4.0);
+            let cb = (-0.1146 * r - 0.3854 * g + 0.5 * b + 128.0).round().clamp(0.0, 255.0) as u8;
+            let cr = (0.5 * r - 0.4542 * g - 0.0458 * b + 128.0).round().clamp(0.0, 255.0) as u8;
+            cb_plane[cy * chroma_width + cx] = cb;
+            cr_plane[cy * chroma_width + cx] = cr;
+        }
+    }
+
+    let mut out = Vec::with_capacity(y_plane.len() + cb_plane.len() + cr_plane.len());
+    out.extend_from_slice(&y_plane);
+    out.extend_from_slice(&cb_plane);
+    out.extend_from_slice(&cr_plane);
+    out
+}
+
+/// Maps a decoded frame's `best_effort_timestamp` (falling back to its PTS)
+/// to a frame index in the stream's own frame rate, so we can tell exactly
+/// which frame we just decoded instead of guessing from packet order. Uses
+/// `frame_rate` (the stream's own, e.g. from `Stream::rate`) rather than the
+/// composition fps, since a source decoded at its native cadence doesn't
+/// land on a composition-fps grid.
+fn frame_index_for_timestamp(frame: &Video, time_base: ffmpeg::Rational, frame_rate: ffmpeg::Rational) -> i64 {
+    let ts = frame.timestamp().or(frame.pts()).unwrap_or(0);
+    let time_seconds = ts as f64 * time_base.0 as f64 / time_base.1 as f64;
+    let frame_duration = frame_rate.1 as f64 / frame_rate.0 as f64;
+    (time_seconds / frame_duration).round() as i64
+}
+
+/// Decodes packets forward from the stream's current position, discarding
+/// decoded frames whose computed index is below `target_frame`, and returns
+/// the pixels, in `output_format`, of the first frame whose index matches (or, failing an
+/// exact match before EOF, the first one at or after it) alongside whether
+/// that match was exact. `target_frame` is given in the composition's
+/// `video_fps`, so it's converted once up front into an index in the
+/// stream's own `frame_rate` — the same units `frame_index_for_timestamp`
+/// computes per decoded frame — rather than re-deriving a composition-fps
+/// index per frame, which can round differently than the target whenever
+/// the two rates differ. The decoder is flushed once the packet stream is
+/// exhausted so the final frame of a clip is still reachable. Returns
+/// `ffmpeg::Error::Eof` if `target_frame` is past the end of the stream.
+#[allow(clippy::too_many_arguments)]
+fn decode_exact_frame(
+    input: &mut ffmpeg::format::context::Input,
+    decoder: &mut ffmpeg::decoder::Video,
+    scaler: &mut Context,
+    stream_index: usize,
+    time_base: ffmpeg::Rational,
+    frame_rate: ffmpeg::Rational,
+    target_frame: u32,
+    video_fps: u32,
+    tone_map_target: ToneMapTarget,
+    output_format: OutputFormat,
+    target_width: u32,
+    target_height: u32,
+) -> Result<(Vec<u8>, bool), ffmpeg::Error> {
+    let target_time = target_frame as f64 / video_fps as f64;
+    let target_index = (target_time * frame_rate.0 as f64 / frame_rate.1 as f64).round() as i64;
+
+    let transfer = decoder.color_transfer_characteristic();
+    let needs_tonemap =
+        tone_map_target == ToneMapTarget::SdrBt709 && is_hdr(transfer, decoder.color_primaries());
+
+    let mut rgb_from_decoded = |decoded: &Video| -> Result<Vec<u8>, ffmpeg::Error> {
+        if needs_tonemap {
+            return tonemap_hdr_frame(decoded, transfer, output_format, target_width, target_height);
+        }
+
+        let mut scaled = Video::empty();
+        scaler.run(decoded, &mut scaled)?;
+        Ok(copy_frame_planes(&scaled, output_format))
+    };
+
+    let mut decoded = Video::empty();
+
+    for (stream, packet) in input.packets() {
+        if stream.index() != stream_index {
+            continue;
+        }
+
+        decoder.send_packet(&packet)?;
+
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let index = frame_index_for_timestamp(&decoded, time_base, frame_rate);
+            if index < target_index {
+                continue;
+            }
+            return Ok((rgb_from_decoded(&decoded)?, index == target_index));
+        }
+    }
+
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        let index = frame_index_for_timestamp(&decoded, time_base, frame_rate);
+        if index < target_index {
+            continue;
+        }
+        return Ok((rgb_from_decoded(&decoded)?, index == target_index));
+    }
+
+    Err(ffmpeg::Error::Eof)
+}
+
+/// Routes a source to a worker in the pool, keeping all requests for the
+/// same file on the same thread so its `OpenDecoder` state stays coherent.
+fn bucket_for(src: &str, pool_size: usize) -> usize {
+    let mut hash: u64 = 14695981039346656037;
+    for byte in src.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(1099511628211);
+    }
+    (hash as usize) % pool_size
+}
+
+fn service_request(
+    decoders: &mut HashMap<String, OpenDecoder>,
+    request: &FrameRequest,
+    video_fps: u32,
+) {
+    let result = (|| -> Result<Vec<u8>, io::Error> {
+        if !decoders.contains_key(&request.src) {
+            let open = OpenDecoder::open(&request.src)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+            decoders.insert(request.src.clone(), open);
+        }
+
+        decoders
+            .get_mut(&request.src)
+            .unwrap()
+            .decode(
+                request.frame,
+                request.width,
+                request.height,
+                video_fps,
+                request.tone_map_target,
+                request.scaling_algorithm,
+                request.output_format,
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+    })();
+
+    let _ = request.reply.send(result);
+}
+
+/// Spins up a pool of long-lived decoder workers (one per available core,
+/// following Av1an's use of `available_parallelism`), each keeping its own
+/// `HashMap<String, OpenDecoder>` alive for the life of the render. Frame
+/// requests are hashed onto the worker that owns the matching source, so
+/// independent layers decode concurrently and a source's container is only
+/// ever opened once. `video_signals` names, per source, the frames it's
+/// about to be asked for; each worker opens and seeks near the earliest of
+/// them for its sources up front, so the first real request doesn't pay
+/// for opening the container and seeking from scratch.
 pub fn process_frames(
     video_signals: HashMap<String, HashMap<u16, u8, RandomState>, RandomState>,
-) -> (
-    Sender<std::string::String>,
-    std::sync::mpsc::Receiver<std::string::String>,
-) {
+    video_fps: u32,
+) -> Sender<FrameRequest> {
     ffmpeg::init().unwrap();
 
-    let mut videos: HashMap<String, ffmpeg::format::context::Input> = HashMap::new();
-    for command in video_signals {
-        let src = command.0;
-        let map = command.1;
-
-        let mut frames = map.keys();
-        // TODO: Might be out of order;
-        // TODO: remove unwrap
-        let first_frame = *frames.next().unwrap();
-
-        let mut stream_input = ffmpeg::format::input(&src).unwrap();
-        let stream = stream_input
-            .streams_mut()
-            .find(|s| s.parameters().medium() == Type::Video)
-            .unwrap();
-        let time_base = stream.time_base();
+    let pool_size = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
 
-        let position = (first_frame as f64 * time_base.1 as f64 / time_base.0 as f64) as i64;
+    let mut warm_up: Vec<Vec<(String, u32)>> = vec![Vec::new(); pool_size];
+    for (src, frames) in &video_signals {
+        if let Some(&first_frame) = frames.keys().min() {
+            warm_up[bucket_for(src, pool_size)].push((src.clone(), first_frame as u32));
+        }
+    }
 
-        stream_input.seek(position, ..position).unwrap();
+    let mut worker_senders: Vec<Sender<FrameRequest>> = Vec::with_capacity(pool_size);
+    for worker_warm_up in warm_up {
+        let (send_worker, receive_worker) = mpsc::channel::<FrameRequest>();
+        worker_senders.push(send_worker);
 
-        print_debug(format!("Seeked to frame ({}): {}", src, first_frame));
+        thread::spawn(move || {
+            let mut decoders: HashMap<String, OpenDecoder> = HashMap::new();
+
+            for (src, first_frame) in worker_warm_up {
+                match OpenDecoder::open(&src) {
+                    Ok(mut decoder) => {
+                        let seeked = seek_to_frame(
+                            &mut decoder.input,
+                            &mut decoder.decoder,
+                            decoder.time_base,
+                            first_frame,
+                            video_fps,
+                        );
+                        match seeked {
+                            // Any frame from `first_frame` on can now be reached by
+                            // decoding forward, so `decode` need not seek again for
+                            // it (a source whose earliest frame is 0 is still sought).
+                            Ok(()) => decoder.last_frame = first_frame.checked_sub(1),
+                            Err(err) => {
+                                print_debug(format!("Failed to warm decoder for {}: {}", src, err));
+                            }
+                        }
+                        decoders.insert(src, decoder);
+                    }
+                    Err(err) => {
+                        print_debug(format!("Failed to open {} while warming decoder pool: {}", src, err));
+                    }
+                }
+            }
 
-        videos.insert(src, stream_input);
+            while let Ok(request) = receive_worker.recv() {
+                service_request(&mut decoders, &request, video_fps);
+            }
+        });
     }
 
-    let (send_input, receive_input) = mpsc::channel::<String>();
-    let (send_output, receive_output) = mpsc::channel::<String>();
+    let (send_input, receive_input) = mpsc::channel::<FrameRequest>();
 
-    thread::spawn(move || loop {
-        let message = match receive_input.recv() {
-            Ok(message) => message,
-            Err(_) => {
-                break;
+    thread::spawn(move || {
+        for request in receive_input {
+            let worker = &worker_senders[bucket_for(&request.src, pool_size)];
+            if let Err(err) = worker.send(request) {
+                print_debug(format!("Decoder worker gone, dropping request: {}", err));
             }
-        };
-        println!("Got message from main thread: {}", message);
-        send_output.send(message).unwrap();
-        break;
+        }
     });
 
-    return (send_input, receive_output);
+    send_input
 }
 
 pub fn get_video_frame(layer: VideoLayer, video_fps: u32) -> Result<Vec<u8>, std::io::Error> {
     ffmpeg::init().unwrap();
 
-    let time: f64 = (layer.frame as f64) / (video_fps as f64);
-
-    // TODO: Improve so only needs to open once
-    let mut stream_input = ffmpeg::format::input(&layer.src)?;
-    let mut input = ffmpeg::format::input(&layer.src)?;
+    let (_avio, mut input) = open_input(&layer.src)?;
 
-    let stream = stream_input
-        .streams_mut()
-        .find(|s| s.parameters().medium() == Type::Video)
+    let stream = input
+        .streams()
+        .best(Type::Video)
         .ok_or(ffmpeg::Error::StreamNotFound)?;
-    let time_base = stream.time_base();
-    let position = (time * time_base.1 as f64 / time_base.0 as f64) as i64;
-
-    input.seek(position, ..position)?;
-
     let stream_index = stream.index();
+    let time_base = stream.time_base();
+    let frame_rate = stream.rate();
     let context_decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
 
     let mut decoder = context_decoder.decoder().video()?;
@@ -96,67 +833,155 @@ pub fn get_video_frame(layer: VideoLayer, video_fps: u32) -> Result<Vec<u8>, std
         decoder.format(),
         decoder.width(),
         decoder.height(),
-        Pixel::RGB24,
+        layer.output_format.pixel(),
+        layer.width,
+        layer.height,
+        layer.scaling_algorithm.flags(),
+    )?;
+
+    seek_to_frame(&mut input, &mut decoder, time_base, layer.frame, video_fps)?;
+
+    let result = decode_exact_frame(
+        &mut input,
+        &mut decoder,
+        &mut scaler,
+        stream_index,
+        time_base,
+        frame_rate,
+        layer.frame,
+        video_fps,
+        layer.tone_map_target,
+        layer.output_format,
         layer.width,
         layer.height,
+    );
+
+    match result {
+        Ok((frame, _exact)) => Ok(frame),
+        Err(ffmpeg::Error::Eof) => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Requested frame {} is past the end of the stream", layer.frame),
+        )),
+        Err(err) => {
+            handle_error(&err);
+            Err(io::Error::new(io::ErrorKind::Other, "Could not create pixmap"))
+        }
+    }
+}
+
+const SCENE_DETECT_WIDTH: u32 = 64;
+const SCENE_DETECT_HEIGHT: u32 = 36;
+const SCENE_DETECT_HISTOGRAM_BINS: usize = 32;
+// A hard cut on otherwise low-texture content (e.g. a flash to black) can
+// leave the histogram shape nearly unchanged while the frame's overall
+// brightness jumps, so we also flag a cut on a large mean-luma swing.
+const SCENE_DETECT_MEAN_LUMA_SPIKE: f32 = 0.15;
+
+/// Builds a normalized intensity histogram and the mean luma (both in
+/// [0, 1]) of a small grayscale frame produced by the scene-detection
+/// scaler.
+fn luma_histogram(gray: &Video) -> ([f32; SCENE_DETECT_HISTOGRAM_BINS], f32) {
+    let mut counts = [0u32; SCENE_DETECT_HISTOGRAM_BINS];
+    let mut sum: u64 = 0;
+
+    let stride = gray.stride(0);
+    let width = gray.width() as usize;
+    let height = gray.height() as usize;
+    let data = gray.data(0);
+
+    for line in 0..height {
+        for &pixel in &data[line * stride..line * stride + width] {
+            counts[pixel as usize * SCENE_DETECT_HISTOGRAM_BINS / 256] += 1;
+            sum += pixel as u64;
+        }
+    }
+
+    let pixel_count = (width * height) as f32;
+    let mut histogram = [0.0; SCENE_DETECT_HISTOGRAM_BINS];
+    for (bin, count) in histogram.iter_mut().zip(counts.iter()) {
+        *bin = *count as f32 / pixel_count;
+    }
+    let mean_luma = sum as f32 / pixel_count / 255.0;
+
+    (histogram, mean_luma)
+}
+
+/// Decodes `src` once and returns the frame indices (in decode order) where
+/// a scene transition occurs, so callers can auto-select poster frames or
+/// build a sparse preview strip without guessing. Each frame is downscaled
+/// to a small grayscale plane through the same `scaling::Context` used
+/// elsewhere in this module and reduced to a normalized intensity
+/// histogram; a cut is flagged when the summed absolute difference between
+/// consecutive histograms exceeds `threshold`, or when the mean luma
+/// spikes even if the histograms look similar. The first frame is always
+/// included so callers have at least one representative frame to show.
+pub fn detect_scene_changes(src: &str, threshold: f32) -> Result<Vec<u32>, std::io::Error> {
+    ffmpeg::init().unwrap();
+
+    let (_avio, mut input) = open_input(src)?;
+    let stream = input
+        .streams()
+        .best(Type::Video)
+        .ok_or(ffmpeg::Error::StreamNotFound)?;
+    let stream_index = stream.index();
+    let context_decoder = ffmpeg::codec::context::Context::from_parameters(stream.parameters())?;
+    let mut decoder = context_decoder.decoder().video()?;
+
+    let mut scaler = Context::get(
+        decoder.format(),
+        decoder.width(),
+        decoder.height(),
+        Pixel::GRAY8,
+        SCENE_DETECT_WIDTH,
+        SCENE_DETECT_HEIGHT,
         Flags::BILINEAR,
     )?;
 
-    let mut process_frame =
-        |decoder: &mut ffmpeg::decoder::Video| -> Result<Vec<u8>, ffmpeg::Error> {
-            let mut input = Video::empty();
-            decoder.receive_frame(&mut input)?;
-            let mut rgb_frame = Video::empty();
-            scaler.run(&input, &mut rgb_frame)?;
-
-            // https://github.com/zmwangx/rust-ffmpeg/issues/64
-            let stride = rgb_frame.stride(0);
-            let byte_width: usize = 3 * rgb_frame.width() as usize;
-            let height: usize = rgb_frame.height() as usize;
-            let mut new_data: Vec<u8> = Vec::with_capacity(byte_width * height);
-            for line in 0..height {
-                let begin = line * stride;
-                let end = begin + byte_width;
-                new_data.append(&mut rgb_frame.data(0)[begin..end].to_vec());
-            }
+    let mut cuts = Vec::new();
+    let mut previous: Option<([f32; SCENE_DETECT_HISTOGRAM_BINS], f32)> = None;
+    let mut frame_index: u32 = 0;
 
-            Ok(new_data)
+    let mut observe_frame = |decoded: &Video| -> Result<(), ffmpeg::Error> {
+        let mut gray = Video::empty();
+        scaler.run(decoded, &mut gray)?;
+        let (histogram, mean_luma) = luma_histogram(&gray);
+
+        let is_cut = match &previous {
+            Some((prev_histogram, prev_mean_luma)) => {
+                let histogram_delta: f32 = histogram
+                    .iter()
+                    .zip(prev_histogram.iter())
+                    .map(|(a, b)| (a - b).abs())
+                    .sum();
+                histogram_delta > threshold
+                    || (mean_luma - prev_mean_luma).abs() > SCENE_DETECT_MEAN_LUMA_SPIKE
+            }
+            None => true,
         };
 
-    let mut frame = Vec::new();
+        if is_cut {
+            cuts.push(frame_index);
+        }
+        previous = Some((histogram, mean_luma));
+        frame_index += 1;
+        Ok(())
+    };
 
+    let mut decoded = Video::empty();
     for (stream, packet) in input.packets() {
-        if stream.index() == stream_index {
-            // -1 because uf 67 and we want to process 66.66 -> rounding error
-            if (packet.dts().unwrap() - 1) > position {
-                break;
-            }
-            loop {
-                decoder.send_packet(&packet)?;
-                let rgb_frame = process_frame(&mut decoder);
-
-                if rgb_frame.is_err() {
-                    let err = rgb_frame.err().unwrap();
-                    if err.to_string().contains("Resource temporarily unavailable") {
-                        // Need to send another packet
-                    } else {
-                        handle_error(&err);
-                    }
-                } else {
-                    frame = rgb_frame.unwrap();
-                    break;
-                }
-            }
+        if stream.index() != stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            observe_frame(&decoded)?;
         }
     }
 
-    let res = match frame.len() {
-        0 => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "Could not create pixmap",
-        )),
-        _ => Ok(frame),
-    };
+    decoder.send_eof()?;
+    while decoder.receive_frame(&mut decoded).is_ok() {
+        observe_frame(&decoded)?;
+    }
 
-    return res;
+    Ok(cuts)
 }